@@ -0,0 +1,593 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+#[cfg(feature = "dot")]
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "dot")]
+use rustls::pki_types::ServerName;
+#[cfg(feature = "dot")]
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+/// How long to wait for a response before giving up
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest message we are willing to receive. Plain DNS over UDP is capped at 512
+/// bytes, but EDNS allows servers to send up to the full UDP payload size, and other
+/// transports are bounded by the 16-bit length used by TCP framing.
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// Size of the fixed header every DNS message starts with
+const HEADER_SIZE: usize = 12;
+/// The transaction ID occupies the first two bytes of the header
+const ID_SIZE: usize = 2;
+/// Offset and mask of the TC bit, which lives in the third byte of the header
+const FLAGS_BYTE_OFFSET: usize = 2;
+const TRUNCATION_MASK: u8 = 0b0000_0010;
+
+/// Media type for wire-format DNS messages carried over HTTP (RFC 8484)
+#[cfg(feature = "doh")]
+const DNS_MESSAGE_MEDIA_TYPE: &str = "application/dns-message";
+
+#[derive(Debug)]
+pub enum TransportError {
+    Io(io::Error),
+    /// The DoH server answered with a status other than 200 OK
+    HttpStatus(u16),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::HttpStatus(status) => write!(f, "unexpected HTTP status {status}"),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::HttpStatus(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Sends `message` to `server` over UDP and returns the raw response, waiting at most
+/// `DEFAULT_TIMEOUT` for it to arrive.
+pub fn query_udp(server: SocketAddr, message: &[u8]) -> io::Result<Vec<u8>> {
+    query_udp_with_timeout(server, message, DEFAULT_TIMEOUT)
+}
+
+/// Same as `query_udp`, but with a caller-provided timeout for the response.
+///
+/// The response is returned as-is, even if it has the TC bit set. Use `is_truncated`
+/// to check whether the query should be retried over TCP.
+pub fn query_udp_with_timeout(
+    server: SocketAddr,
+    message: &[u8],
+    timeout: Duration,
+) -> io::Result<Vec<u8>> {
+    let bind_addr: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr)?;
+    // Connecting makes the OS drop datagrams that don't come from `server`
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.send(message)?;
+
+    let mut buf = vec![0; MAX_MESSAGE_SIZE];
+    let len = socket.recv(&mut buf)?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// Sends `message` to `server` over TCP and returns the raw response, waiting at most
+/// `DEFAULT_TIMEOUT` for it to arrive.
+pub fn query_tcp(server: SocketAddr, message: &[u8]) -> io::Result<Vec<u8>> {
+    query_tcp_with_timeout(server, message, DEFAULT_TIMEOUT)
+}
+
+/// Same as `query_tcp`, but with a caller-provided timeout for connecting, sending and
+/// receiving.
+pub fn query_tcp_with_timeout(
+    server: SocketAddr,
+    message: &[u8],
+    timeout: Duration,
+) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write_framed(&mut stream, message)?;
+    read_framed(&mut stream)
+}
+
+/// Sends `message` over UDP, retrying over TCP if the response has the TC bit set, and
+/// returns whichever response is final.
+///
+/// Responses shorter than a DNS header, or whose ID doesn't match the query's, are
+/// rejected with `io::ErrorKind::InvalidData`.
+pub fn resolve(server: SocketAddr, message: &[u8]) -> Result<Vec<u8>, TransportError> {
+    let response = query_udp(server, message)?;
+    check_response_header(message, &response)?;
+    if is_truncated(&response) {
+        let response = query_tcp(server, message)?;
+        check_response_header(message, &response)?;
+        return Ok(response);
+    }
+    Ok(response)
+}
+
+/// Checks that `response` holds a complete header answering `query`
+fn check_response_header(query: &[u8], response: &[u8]) -> io::Result<()> {
+    if response.len() < HEADER_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "response is shorter than a DNS header",
+        ));
+    }
+    if query.get(..ID_SIZE) != Some(&response[..ID_SIZE]) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "response ID does not match the query",
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the TC bit of a raw DNS message. Returns `false` if the message is too short
+/// to contain the flags.
+pub fn is_truncated(message: &[u8]) -> bool {
+    message
+        .get(FLAGS_BYTE_OFFSET)
+        .is_some_and(|flags| flags & TRUNCATION_MASK != 0)
+}
+
+/// Sends `message` to a DNS-over-HTTPS endpoint as an RFC 8484 POST request and returns
+/// the response body. `url` is the full URL of the endpoint, e.g.
+/// `https://dns.example/dns-query`.
+#[cfg(feature = "doh")]
+pub fn query_doh(url: &str, message: &[u8]) -> Result<Vec<u8>, TransportError> {
+    let response = ureq::post(url)
+        .timeout(DEFAULT_TIMEOUT)
+        .set("Content-Type", DNS_MESSAGE_MEDIA_TYPE)
+        .set("Accept", DNS_MESSAGE_MEDIA_TYPE)
+        .send_bytes(message)
+        .map_err(|e| match e {
+            ureq::Error::Status(status, _) => TransportError::HttpStatus(status),
+            ureq::Error::Transport(e) => TransportError::Io(io::Error::other(e)),
+        })?;
+    // ureq only reports 4xx and 5xx as errors, but anything other than 200 means we
+    // did not get a DNS message back
+    if response.status() != 200 {
+        return Err(TransportError::HttpStatus(response.status()));
+    }
+
+    // Read one byte past the limit so an oversized body is reported rather than cut off
+    let mut body = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_MESSAGE_SIZE as u64 + 1)
+        .read_to_end(&mut body)?;
+    if body.len() > MAX_MESSAGE_SIZE {
+        return Err(TransportError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "DoH response is larger than a DNS message can be",
+        )));
+    }
+    Ok(body)
+}
+
+/// Sends `message` to a DNS-over-TLS server (RFC 7858) and returns the response, waiting
+/// at most `DEFAULT_TIMEOUT` for it to arrive. The server certificate is verified against
+/// `server_name` using the Mozilla root store from `webpki-roots`.
+#[cfg(feature = "dot")]
+pub fn query_dot(
+    server: SocketAddr,
+    server_name: &str,
+    message: &[u8],
+) -> Result<Vec<u8>, TransportError> {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| TransportError::Io(io::Error::other(e)))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    query_dot_with_config(
+        server,
+        server_name,
+        message,
+        Arc::new(config),
+        DEFAULT_TIMEOUT,
+    )
+}
+
+/// Same as `query_dot`, but with a caller-provided TLS configuration, e.g. to trust a
+/// private CA, and a caller-provided timeout for connecting, sending and receiving.
+#[cfg(feature = "dot")]
+pub fn query_dot_with_config(
+    server: SocketAddr,
+    server_name: &str,
+    message: &[u8],
+    config: Arc<ClientConfig>,
+    timeout: Duration,
+) -> Result<Vec<u8>, TransportError> {
+    let server_name = ServerName::try_from(server_name.to_owned())
+        .map_err(|e| TransportError::Io(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+    let conn = ClientConnection::new(config, server_name)
+        .map_err(|e| TransportError::Io(io::Error::other(e)))?;
+
+    let socket = TcpStream::connect_timeout(&server, timeout)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.set_write_timeout(Some(timeout))?;
+    let mut stream = StreamOwned::new(conn, socket);
+
+    write_framed(&mut stream, message)?;
+    Ok(read_framed(&mut stream)?)
+}
+
+/// Writes `message` with the 2-byte length prefix used by stream transports
+fn write_framed<W: Write>(stream: &mut W, message: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(message.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "message is too long to be length-prefixed",
+        )
+    })?;
+    // Send the prefix and message together so they aren't split into separate segments
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend_from_slice(message);
+    stream.write_all(&framed)?;
+    stream.flush()
+}
+
+/// Reads one message with a 2-byte length prefix from a stream transport
+fn read_framed<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut message = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// A 12-byte query header with ID 0xabcd and RD set
+    const QUERY: [u8; 12] = [0xab, 0xcd, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+
+    /// Answers a single datagram on `socket` with `reply` and hands back what it received
+    fn udp_responder(socket: UdpSocket, reply: Vec<u8>) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, peer) = socket.recv_from(&mut buf).unwrap();
+            socket.send_to(&reply, peer).unwrap();
+            buf[..len].to_vec()
+        })
+    }
+
+    #[test]
+    fn test_query_udp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.local_addr().unwrap();
+        let reply = vec![0xab, 0xcd, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0, 0xde, 0xad];
+        let responder = udp_responder(socket, reply.clone());
+
+        let response = query_udp(server, &QUERY).unwrap();
+        assert_eq!(response, reply);
+        assert_eq!(responder.join().unwrap(), QUERY);
+    }
+
+    #[test]
+    fn test_query_udp_timeout() {
+        // Kept alive so the query is delivered, but never answered
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.local_addr().unwrap();
+
+        let err = query_udp_with_timeout(server, &QUERY, Duration::from_millis(100)).unwrap_err();
+        assert!(
+            matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ),
+            "unexpected error kind {:?}",
+            err.kind()
+        );
+        drop(socket);
+    }
+
+    /// Binds a TCP listener and a UDP socket on the same localhost port, so both
+    /// transports in `resolve` reach the same mock server
+    fn bind_udp_and_tcp() -> (SocketAddr, UdpSocket, TcpListener) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap();
+        let socket = UdpSocket::bind(server).unwrap();
+        (server, socket, listener)
+    }
+
+    #[test]
+    fn test_resolve_tcp_fallback() {
+        let (server, socket, listener) = bind_udp_and_tcp();
+        let truncated = vec![0xab, 0xcd, 0x83, 0x80, 0, 1, 0, 0, 0, 0, 0, 0];
+        let full = vec![
+            0xab, 0xcd, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0, 0xc0, 0x0c, 0, 1,
+        ];
+        let udp = udp_responder(socket, truncated);
+        let tcp = {
+            let full = full.clone();
+            thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let query = read_framed(&mut stream).unwrap();
+                write_framed(&mut stream, &full).unwrap();
+                query
+            })
+        };
+
+        let response = resolve(server, &QUERY).unwrap();
+        assert_eq!(response, full);
+        assert_eq!(udp.join().unwrap(), QUERY);
+        assert_eq!(tcp.join().unwrap(), QUERY);
+    }
+
+    #[test]
+    fn test_resolve_without_truncation() {
+        let (server, socket, listener) = bind_udp_and_tcp();
+        let reply = vec![0xab, 0xcd, 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0];
+        let udp = udp_responder(socket, reply.clone());
+
+        let response = resolve(server, &QUERY).unwrap();
+        assert_eq!(response, reply);
+        udp.join().unwrap();
+
+        listener.set_nonblocking(true).unwrap();
+        let err = listener.accept().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn test_resolve_short_response() {
+        for len in [0, 2, HEADER_SIZE - 1] {
+            let (server, socket, _listener) = bind_udp_and_tcp();
+            // Even with the TC bit set, a short response must not trigger the fallback
+            let mut reply = vec![0xab, 0xcd, 0x83, 0x80, 0, 1, 0, 0, 0, 0, 0];
+            reply.truncate(len);
+            let udp = udp_responder(socket, reply);
+
+            let err = resolve(server, &QUERY).unwrap_err();
+            assert!(
+                matches!(&err, TransportError::Io(e) if e.kind() == io::ErrorKind::InvalidData),
+                "unexpected error {err:?}"
+            );
+            udp.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_resolve_mismatched_id() {
+        let (server, socket, _listener) = bind_udp_and_tcp();
+        let reply = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0];
+        let udp = udp_responder(socket, reply);
+
+        let err = resolve(server, &QUERY).unwrap_err();
+        assert!(
+            matches!(&err, TransportError::Io(e) if e.kind() == io::ErrorKind::InvalidData),
+            "unexpected error {err:?}"
+        );
+        udp.join().unwrap();
+    }
+
+    #[test]
+    fn test_is_truncated() {
+        assert!(!is_truncated(&[]));
+        assert!(!is_truncated(&[0xab, 0xcd]));
+        assert!(is_truncated(&[0xab, 0xcd, 0x82, 0x00]));
+        assert!(!is_truncated(&[0xab, 0xcd, 0x81, 0x80]));
+    }
+}
+
+#[cfg(all(test, feature = "doh"))]
+mod doh_tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    const QUERY: [u8; 12] = [0xab, 0xcd, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+
+    /// What the mock server saw in the request
+    struct ReceivedRequest {
+        content_type: Option<String>,
+        body: Vec<u8>,
+    }
+
+    /// Serves a single HTTP/1.1 request on localhost, answering with `status_line` and
+    /// `body`. Returns the URL to query and a handle yielding the received request.
+    fn http_responder(
+        status_line: &'static str,
+        body: Vec<u8>,
+    ) -> (String, thread::JoinHandle<ReceivedRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/dns-query", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut content_type = None;
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    let value = value.trim();
+                    if name.eq_ignore_ascii_case("content-type") {
+                        content_type = Some(value.to_owned());
+                    } else if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.parse().unwrap();
+                    }
+                }
+            }
+            let mut request_body = vec![0; content_length];
+            reader.read_exact(&mut request_body).unwrap();
+
+            let mut stream = stream;
+            write!(
+                stream,
+                "HTTP/1.1 {status_line}\r\nContent-Type: {DNS_MESSAGE_MEDIA_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(&body).unwrap();
+
+            ReceivedRequest {
+                content_type,
+                body: request_body,
+            }
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_query_doh() {
+        let reply = vec![0xab, 0xcd, 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0];
+        let (url, server) = http_responder("200 OK", reply.clone());
+
+        let response = query_doh(&url, &QUERY).unwrap();
+        assert_eq!(response, reply);
+
+        let request = server.join().unwrap();
+        assert_eq!(
+            request.content_type.as_deref(),
+            Some(DNS_MESSAGE_MEDIA_TYPE)
+        );
+        assert_eq!(request.body, QUERY);
+    }
+
+    #[test]
+    fn test_query_doh_error_status() {
+        for (status_line, status) in [("404 Not Found", 404), ("500 Internal Server Error", 500)] {
+            let (url, server) = http_responder(status_line, Vec::new());
+            let err = query_doh(&url, &QUERY).unwrap_err();
+            assert!(
+                matches!(err, TransportError::HttpStatus(s) if s == status),
+                "unexpected error {err:?}"
+            );
+            server.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_query_doh_non_200_success_status() {
+        // ureq treats 2xx as success, so this exercises our own status check
+        let (url, server) = http_responder("204 No Content", Vec::new());
+        let err = query_doh(&url, &QUERY).unwrap_err();
+        assert!(
+            matches!(err, TransportError::HttpStatus(204)),
+            "unexpected error {err:?}"
+        );
+        server.join().unwrap();
+    }
+}
+
+#[cfg(all(test, feature = "dot"))]
+mod dot_tests {
+    use super::*;
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use rustls::{ServerConfig, ServerConnection};
+    use std::net::TcpListener;
+    use std::thread;
+
+    const QUERY: [u8; 12] = [0xab, 0xcd, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    const CERT_NAME: &str = "dns.test";
+
+    /// Serves a single framed DoT exchange on localhost with a freshly generated
+    /// self-signed certificate for `CERT_NAME`, answering with `reply`. Returns the
+    /// address to query, a client config trusting the certificate, and a handle yielding
+    /// the received query (or `None` if the handshake or read failed).
+    fn tls_responder(
+        reply: Vec<u8>,
+    ) -> (
+        SocketAddr,
+        Arc<ClientConfig>,
+        thread::JoinHandle<Option<Vec<u8>>>,
+    ) {
+        let certified = rcgen::generate_simple_self_signed(vec![CERT_NAME.to_owned()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key =
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let server_config = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let conn = ServerConnection::new(Arc::new(server_config)).unwrap();
+            let mut stream = StreamOwned::new(conn, socket);
+            let query = read_framed(&mut stream).ok()?;
+            write_framed(&mut stream, &reply).ok()?;
+            Some(query)
+        });
+        (addr, Arc::new(client_config), handle)
+    }
+
+    #[test]
+    fn test_query_dot() {
+        let reply = vec![0xab, 0xcd, 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0];
+        let (server, config, responder) = tls_responder(reply.clone());
+
+        let response =
+            query_dot_with_config(server, CERT_NAME, &QUERY, config, DEFAULT_TIMEOUT).unwrap();
+        assert_eq!(response, reply);
+        assert_eq!(responder.join().unwrap().as_deref(), Some(&QUERY[..]));
+    }
+
+    #[test]
+    fn test_query_dot_wrong_server_name() {
+        let (server, config, responder) = tls_responder(Vec::new());
+
+        let err = query_dot_with_config(server, "other.test", &QUERY, config, DEFAULT_TIMEOUT)
+            .unwrap_err();
+        let tls_err = match &err {
+            TransportError::Io(e) => e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()),
+            TransportError::HttpStatus(_) => None,
+        };
+        assert!(
+            matches!(tls_err, Some(rustls::Error::InvalidCertificate(_))),
+            "unexpected error {err:?}"
+        );
+        assert_eq!(responder.join().unwrap(), None);
+    }
+}